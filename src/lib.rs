@@ -1,7 +1,11 @@
+use chardetng::EncodingDetector;
 use clap::{App, Arg};
+use encoding_rs::Encoding;
+use encoding_rs_io::DecodeReaderBytesBuilder;
+use std::collections::VecDeque;
 use std::error::Error;
 use std::fs::File;
-use std::io::{self, BufRead, BufReader, Read};
+use std::io::{self, BufRead, BufReader, Read, Write};
 
 type MyResult<T> = Result<T, Box<dyn Error>>;
 
@@ -9,20 +13,62 @@ type MyResult<T> = Result<T, Box<dyn Error>>;
 pub struct Config {
     files: Vec<String>,
     read: ReadType,
+    // The encoding to decode input with, or `None` to sniff it per file.
+    encoding: Option<&'static Encoding>,
+    // Print binary files raw instead of skipping them with a notice.
+    binary: bool,
+    // Byte that terminates a line: `\n` normally, `\0` under `-z`.
+    delimiter: u8,
 }
 
 #[derive(Debug)]
 enum ReadType {
-    Lines(usize),
-    Bytes(usize),
+    Lines(Count),
+    Bytes(Count),
+}
+
+// A count is either the first `n` records from the start of the file, or,
+// when the user passed a negative number, every record except the last `n`.
+#[derive(Debug)]
+enum Count {
+    First(usize),
+    AllButLast(usize),
+}
+
+impl Count {
+    fn new(n: usize, negative: bool) -> Self {
+        if negative {
+            Self::AllButLast(n)
+        } else {
+            Self::First(n)
+        }
+    }
+}
+
+// A fallible line iterator mirroring the shape of the std `lines()`/`bytes()`
+// iterators: each `next` yields a `MyResult` so a mid-stream read error is
+// surfaced to the caller rather than masquerading as a clean EOF.
+struct Lines<R: BufRead> {
+    reader: R,
+    delimiter: u8,
+}
+
+impl<R: BufRead> Iterator for Lines<R> {
+    type Item = MyResult<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut buf = Vec::new();
+        match self.reader.read_until(self.delimiter, &mut buf) {
+            Ok(0) => None,
+            Ok(_) => Some(Ok(buf)),
+            Err(err) => Some(Err(Box::new(err))),
+        }
+    }
 }
 
 impl ReadType {
     pub fn new_lines(lines: usize) -> Self {
-        Self::Lines(lines)
-    }
-    pub fn new_bytes(bytes: usize) -> Self {
-        Self::Bytes(bytes)
+        Self::Lines(Count::First(lines))
     }
 }
 
@@ -46,6 +92,7 @@ pub fn get_args() -> MyResult<Config> {
                 .value_name("LINES")
                 .help("Number of lines to print")
                 .default_value("10")
+                .allow_hyphen_values(true)
                 .takes_value(true),
         )
         .arg(
@@ -54,22 +101,62 @@ pub fn get_args() -> MyResult<Config> {
                 .value_name("BYTES")
                 .multiple(false)
                 .long("bytes")
-                .help("Amount of bytes to print")
+                .help("Amount of bytes to print (counted after decoding to UTF-8, raw with -a)")
                 .conflicts_with("lines")
+                .allow_hyphen_values(true)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("encoding")
+                .long("encoding")
+                .value_name("LABEL")
+                .help("Encoding to decode input with (default: auto-detect)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("binary")
+                .short("a")
+                .long("binary")
+                .multiple(false)
+                .help("Print binary files raw instead of skipping them")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("zero-terminated")
+                .short("z")
+                .long("zero-terminated")
+                .multiple(false)
+                .help("Line delimiter is NUL, not newline")
+                .takes_value(false),
+        )
         .get_matches();
 
+    let encoding = match matches.value_of("encoding") {
+        Some(label) => {
+            Some(Encoding::for_label(label.as_bytes()).ok_or_else(|| -> Box<dyn Error> {
+                From::from(format!("unknown encoding: {}", label))
+            })?)
+        }
+        None => None,
+    };
+
     Ok(Config {
+        encoding,
+        binary: matches.is_present("binary"),
+        delimiter: if matches.is_present("zero-terminated") {
+            0
+        } else {
+            b'\n'
+        },
         files: matches.values_of_lossy("files").unwrap(),
         read: if !matches.is_present("lines") && !matches.is_present("bytes") {
             ReadType::new_lines(10)
         } else if matches.is_present("bytes") {
-            let val = parse_positive_int(matches.value_of("bytes").unwrap());
-            ReadType::new_bytes(val?)
+            let (val, negative) = parse_byte_count(matches.value_of("bytes").unwrap())?;
+            ReadType::Bytes(Count::new(val, negative))
         } else {
-            let val = parse_positive_int(matches.value_of("lines").unwrap());
-            ReadType::new_lines(val?)
+            let (val, negative) = parse_signed_int(matches.value_of("lines").unwrap())?;
+            ReadType::Lines(Count::new(val, negative))
         },
     })
 }
@@ -80,41 +167,183 @@ pub fn run(config: Config) -> MyResult<()> {
         match open(&file) {
             Err(err) => eprintln!("{}: {}", file, err),
             Ok(buffer) => {
+                let (is_binary, buffer) = match sniff(buffer) {
+                    Ok(sniffed) => sniffed,
+                    Err(err) => {
+                        eprintln!("{}: {}", file, err);
+                        continue;
+                    }
+                };
+                // NUL-delimited input (`find -print0` and friends) is expected
+                // to contain NUL bytes, which the sniffer would otherwise flag
+                // as binary, so skip the check in `-z` mode.
+                if is_binary && !config.binary && config.delimiter != 0 {
+                    eprintln!("headr: '{}': binary file", file);
+                    continue;
+                }
                 if num_files > 1 {
-                    println!("{}==> {} <==", if file_num > 0 { "\n" } else { "" }, file)
+                    let sep = config.delimiter as char;
+                    print!(
+                        "{}==> {} <=={}",
+                        if file_num > 0 { sep.to_string() } else { String::new() },
+                        file,
+                        sep
+                    )
+                }
+                // With `-a` the user wants the bytes verbatim, so skip the
+                // transcoding that would otherwise rewrite non-UTF-8 bytes.
+                if config.binary {
+                    print_file(buffer, &config.read, config.delimiter, file)?;
+                } else {
+                    let decoded = decode(buffer, config.encoding)?;
+                    print_file(decoded, &config.read, config.delimiter, file)?;
                 }
-                print_file(buffer, &config.read)?;
             }
         };
     }
     Ok(())
 }
 
-fn print_file(mut file: Box<dyn BufRead>, read: &ReadType) -> MyResult<()> {
+fn print_file(
+    file: Box<dyn BufRead>,
+    read: &ReadType,
+    delimiter: u8,
+    filename: &str,
+) -> MyResult<()> {
+    let mut out = io::stdout();
     match read {
-        ReadType::Lines(num_lines) => {
-            let mut line = String::new();
-            for _ in 0..*num_lines {
-                let bytes = file.read_line(&mut line)?;
-                if bytes == 0 {
-                    break;
+        ReadType::Lines(Count::First(num_lines)) => {
+            let lines = Lines {
+                reader: file,
+                delimiter,
+            };
+            for (idx, line) in lines.take(*num_lines).enumerate() {
+                match line {
+                    Ok(line) => out.write_all(&line)?,
+                    Err(err) => {
+                        eprintln!("{}: error reading line {}: {}", filename, idx + 1, err);
+                        break;
+                    }
                 }
-                print!("{}", line);
-                line.clear();
             }
             Ok(())
         }
-        ReadType::Bytes(bytes) => {
+        ReadType::Lines(Count::AllButLast(num_lines)) => {
+            // Keep a window of the most recent `num_lines` lines; once it is
+            // full every further line shifts one provably-printable line out
+            // the front. Whatever is still buffered at EOF is the tail we drop.
+            let lines = Lines {
+                reader: file,
+                delimiter,
+            };
+            let mut window: VecDeque<Vec<u8>> = VecDeque::with_capacity(*num_lines);
+            for (idx, line) in lines.enumerate() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(err) => {
+                        eprintln!("{}: error reading line {}: {}", filename, idx + 1, err);
+                        break;
+                    }
+                };
+                if window.len() == *num_lines {
+                    out.write_all(&window.pop_front().unwrap())?;
+                }
+                window.push_back(line);
+            }
+            Ok(())
+        }
+        ReadType::Bytes(Count::First(bytes)) => {
+            // Read in bounded chunks rather than sizing the buffer by the
+            // requested count: with size suffixes `-c 1T` is one keystroke, and
+            // a count-sized `vec![0; *bytes]` would try to allocate a terabyte
+            // regardless of how short the actual input is.
+            const CHUNK: usize = 64 * 1024;
             let mut handle = file.take(*bytes as u64);
-            let mut buffer = vec![0; *bytes];
-            let bytes_read = handle.read(&mut buffer)?;
-            print!("{}", String::from_utf8_lossy(&buffer[..bytes_read]));
+            let mut buffer = vec![0; (*bytes).min(CHUNK)];
+            loop {
+                let bytes_read = handle.read(&mut buffer)?;
+                if bytes_read == 0 {
+                    break;
+                }
+                out.write_all(&buffer[..bytes_read])?;
+            }
 
             Ok(())
         }
+        ReadType::Bytes(Count::AllButLast(bytes)) => {
+            // Same ring-buffer trick over raw bytes: the oldest byte is safe to
+            // emit as soon as the buffer would exceed `bytes`, so we never hold
+            // more than the trailing window even on a non-seekable pipe.
+            let mut window: VecDeque<u8> = VecDeque::with_capacity(*bytes);
+            for byte in file.bytes() {
+                let byte = byte?;
+                window.push_back(byte);
+                if window.len() > *bytes {
+                    out.write_all(&[window.pop_front().unwrap()])?;
+                }
+            }
+            Ok(())
+        }
     }
 }
 
+// Peek the first 8 KiB of a stream to decide whether it is binary, then hand
+// back a reader that replays those sniffed bytes ahead of the untouched
+// remainder so nothing is lost for the subsequent line/byte printing.
+fn sniff(mut reader: Box<dyn BufRead>) -> MyResult<(bool, Box<dyn BufRead>)> {
+    let mut prefix = vec![0u8; 8192];
+    let mut filled = 0;
+    while filled < prefix.len() {
+        let n = reader.read(&mut prefix[filled..])?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    prefix.truncate(filled);
+
+    let is_binary = content_inspector::inspect(&prefix).is_binary();
+    let replayed = io::Cursor::new(prefix).chain(reader);
+    Ok((is_binary, Box::new(BufReader::new(replayed))))
+}
+
+// Wrap a reader so that its bytes are transcoded to UTF-8 before the
+// line/byte limit is applied. With an explicit `encoding` that encoding is
+// forced. With `None` we first honour a UTF-8/UTF-16 BOM (left to
+// `DecodeReaderBytesBuilder`'s own sniffing, which `chardetng` can't do for
+// UTF-16) and otherwise let `chardetng` guess the charset from the content, so
+// no-BOM Latin-1/Shift-JIS files transcode correctly too.
+fn decode(
+    mut reader: Box<dyn BufRead>,
+    encoding: Option<&'static Encoding>,
+) -> MyResult<Box<dyn BufRead>> {
+    let encoding = match encoding {
+        Some(enc) => Some(enc),
+        None => {
+            let peek = reader.fill_buf()?;
+            if peek.is_empty() || has_bom(peek) {
+                // Empty input, or a BOM the builder will sniff and strip itself.
+                None
+            } else {
+                let mut detector = EncodingDetector::new();
+                detector.feed(peek, true);
+                Some(detector.guess(None, true))
+            }
+        }
+    };
+    let decoder = DecodeReaderBytesBuilder::new()
+        .encoding(encoding)
+        .build(reader);
+    Ok(Box::new(BufReader::new(decoder)))
+}
+
+// Does the prefix start with a UTF-8 or UTF-16 byte-order mark?
+fn has_bom(prefix: &[u8]) -> bool {
+    prefix.starts_with(&[0xEF, 0xBB, 0xBF]) // UTF-8
+        || prefix.starts_with(&[0xFF, 0xFE]) // UTF-16LE
+        || prefix.starts_with(&[0xFE, 0xFF]) // UTF-16BE
+}
+
 fn open(filename: &str) -> MyResult<Box<dyn BufRead>> {
     match filename {
         "-" => Ok(Box::new(BufReader::new(io::stdin()))),
@@ -129,6 +358,54 @@ fn parse_positive_int(val: &str) -> MyResult<usize> {
     }
 }
 
+// Resolve a GNU `head` size suffix into its byte multiplier. An empty suffix
+// means a bare count; `None` signals an unrecognized suffix.
+fn byte_multiplier(suffix: &str) -> Option<usize> {
+    let mult = match suffix {
+        "" => 1,
+        "b" => 512,
+        "kB" => 1000,
+        "K" | "KiB" => 1024,
+        "MB" => 1000 * 1000,
+        "M" | "MiB" => 1024 * 1024,
+        "GB" => 1000 * 1000 * 1000,
+        "G" | "GiB" => 1024 * 1024 * 1024,
+        "TB" => 1000 * 1000 * 1000 * 1000,
+        "T" | "TiB" => 1024 * 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    Some(mult)
+}
+
+// Parse the `bytes` argument, honouring an optional leading `-` (see
+// `parse_signed_int`) and a trailing size suffix such as `K`, `MB` or `GiB`.
+fn parse_byte_count(val: &str) -> MyResult<(usize, bool)> {
+    let (negative, rest) = match val.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, val),
+    };
+    let split = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    let (digits, suffix) = rest.split_at(split);
+    let invalid = || -> Box<dyn Error> { From::from(val) };
+
+    let base = parse_positive_int(digits).map_err(|_| invalid())?;
+    let mult = byte_multiplier(suffix).ok_or_else(invalid)?;
+    let total = base.checked_mul(mult).ok_or_else(invalid)?;
+    Ok((total, negative))
+}
+
+fn parse_signed_int(val: &str) -> MyResult<(usize, bool)> {
+    match val.strip_prefix('-') {
+        Some(rest) => match parse_positive_int(rest) {
+            Ok(n) => Ok((n, true)),
+            Err(_) => Err(From::from(val)),
+        },
+        None => parse_positive_int(val).map(|n| (n, false)),
+    }
+}
+
 #[test]
 fn test_parse_positive_int() {
     // 3 is an okay int
@@ -146,3 +423,56 @@ fn test_parse_positive_int() {
     assert!(res.is_err());
     assert_eq!(res.unwrap_err().to_string(), "0".to_string());
 }
+
+#[test]
+fn test_parse_signed_int() {
+    // a plain positive count is not negative
+    let res = parse_signed_int("3");
+    assert!(res.is_ok());
+    assert_eq!(res.unwrap(), (3, false));
+
+    // a leading minus flips the negative flag but keeps the magnitude
+    let res = parse_signed_int("-5");
+    assert!(res.is_ok());
+    assert_eq!(res.unwrap(), (5, true));
+
+    // zero is err, like the positive parser
+    let res = parse_signed_int("0");
+    assert!(res.is_err());
+    assert_eq!(res.unwrap_err().to_string(), "0".to_string());
+
+    // any non-numeric string is err
+    let res = parse_signed_int("foo");
+    assert!(res.is_err());
+    assert_eq!(res.unwrap_err().to_string(), "foo".to_string());
+}
+
+#[test]
+fn test_parse_byte_count() {
+    // a bare count is a multiplier of one
+    assert_eq!(parse_byte_count("10").unwrap(), (10, false));
+
+    // SI and binary suffixes pick the right multiplier
+    assert_eq!(parse_byte_count("1K").unwrap(), (1024, false));
+    assert_eq!(parse_byte_count("1kB").unwrap(), (1000, false));
+    assert_eq!(parse_byte_count("10M").unwrap(), (10 * 1024 * 1024, false));
+    assert_eq!(parse_byte_count("2MB").unwrap(), (2_000_000, false));
+    assert_eq!(parse_byte_count("1b").unwrap(), (512, false));
+
+    // the leading minus still flips the negative flag
+    assert_eq!(parse_byte_count("-4K").unwrap(), (4096, true));
+
+    // an unknown suffix is rejected with the original input
+    let res = parse_byte_count("1Q");
+    assert!(res.is_err());
+    assert_eq!(res.unwrap_err().to_string(), "1Q".to_string());
+}
+
+#[test]
+fn test_count_new() {
+    // a positive count keeps the first N records
+    assert!(matches!(Count::new(5, false), Count::First(5)));
+
+    // a negative count drops the last N records
+    assert!(matches!(Count::new(5, true), Count::AllButLast(5)));
+}